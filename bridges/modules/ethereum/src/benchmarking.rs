@@ -0,0 +1,241 @@
+// Copyright 2019-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Bridges Common.
+
+// Parity Bridges Common is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Bridges Common is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Bridges Common.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Benchmarks for the finality computation in `finalize_blocks`, whose cost scales with the
+//! depth of the unfinalized ancestry, the size of the validator set and the number of empty
+//! step signatures that have to be recovered per header.
+
+use crate::finality::{finalize_blocks, prepare_votes, ValidatorsSets};
+use crate::{BridgeStorage, Storage, Trait};
+use frame_benchmarking::benchmarks;
+use libsecp256k1::{sign, Message, PublicKey, SecretKey};
+use primitives::{public_to_address, Address, Header, SealedEmptyStep, H256, H520};
+use sp_std::{collections::btree_map::BTreeMap, prelude::*};
+
+/// Maximal number of unfinalized headers in the ancestry that we benchmark for. Bounded by the
+/// same pruning depth that the pallet itself enforces on the unfinalized ancestry.
+const MAX_ANCESTRY_DEPTH: u32 = 128;
+
+/// Maximal size of the validator set that we benchmark for.
+const MAX_VALIDATORS_COUNT: u32 = 128;
+
+/// Maximal number of empty step signatures carried by a single header that we benchmark for.
+const MAX_EMPTY_STEPS_COUNT: u32 = 16;
+
+benchmarks! {
+	_ { }
+
+	// Finalizes a chain of `n` headers, signed in turn by a validator set of `v` addresses,
+	// recovering `s` empty step signatures per header. This is the cache-miss path: there is
+	// no cached `FinalityVotes` snapshot, so the whole `n`-long ancestry is replayed.
+	finalize_blocks_cache_miss {
+		let n in 1 .. MAX_ANCESTRY_DEPTH;
+		let v in 3 .. MAX_VALIDATORS_COUNT;
+		let s in 0 .. MAX_EMPTY_STEPS_COUNT;
+
+		let secrets = bench_validator_secrets(v);
+		let validators = secrets.iter().map(address_from_secret).collect::<Vec<_>>();
+		let mut storage = BridgeStorage::<T>::new();
+		let (best_finalized, headers) = import_chain(&mut storage, &validators, &secrets[0], n, s);
+		let last_header = headers.last().expect("n >= 1; qed").clone();
+		let validators_sets = single_validators_set(&validators);
+	}: {
+		finalize_blocks(
+			&storage,
+			best_finalized,
+			&validators_sets,
+			last_header.compute_id(),
+			None,
+			&last_header,
+			u64::max_value(),
+			None,
+		).map_err(|_| "finalize_blocks has failed")?;
+	}
+
+	// Same as `finalize_blocks_cache_miss`, but with a warm `FinalityVotes` cache at the
+	// direct parent of the header being finalized - only the new header's own votes are added.
+	finalize_blocks_cache_hit {
+		let v in 3 .. MAX_VALIDATORS_COUNT;
+		let s in 0 .. MAX_EMPTY_STEPS_COUNT;
+
+		let secrets = bench_validator_secrets(v);
+		let validators = secrets.iter().map(address_from_secret).collect::<Vec<_>>();
+		let mut storage = BridgeStorage::<T>::new();
+		let (best_finalized, headers) = import_chain(&mut storage, &validators, &secrets[0], MAX_ANCESTRY_DEPTH, s);
+		let validators_sets = single_validators_set(&validators);
+		let parent_header = headers[headers.len() - 2].clone();
+		let last_header = headers[headers.len() - 1].clone();
+		cache_finality_votes::<T, _>(&storage, &validators_sets, &parent_header);
+	}: {
+		finalize_blocks(
+			&storage,
+			best_finalized,
+			&validators_sets,
+			last_header.compute_id(),
+			None,
+			&last_header,
+			u64::max_value(),
+			None,
+		).map_err(|_| "finalize_blocks has failed")?;
+	}
+
+	// Same as `finalize_blocks_cache_miss`, but past the `two_thirds_majority_transition`, so
+	// the (slightly more expensive) two-thirds finality threshold is exercised.
+	finalize_blocks_after_two_thirds_transition {
+		let n in 1 .. MAX_ANCESTRY_DEPTH;
+		let v in 3 .. MAX_VALIDATORS_COUNT;
+		let s in 0 .. MAX_EMPTY_STEPS_COUNT;
+
+		let secrets = bench_validator_secrets(v);
+		let validators = secrets.iter().map(address_from_secret).collect::<Vec<_>>();
+		let mut storage = BridgeStorage::<T>::new();
+		let (best_finalized, headers) = import_chain(&mut storage, &validators, &secrets[0], n, s);
+		let last_header = headers.last().expect("n >= 1; qed").clone();
+		let validators_sets = single_validators_set(&validators);
+	}: {
+		finalize_blocks(
+			&storage,
+			best_finalized,
+			&validators_sets,
+			last_header.compute_id(),
+			None,
+			&last_header,
+			0,
+			None,
+		).map_err(|_| "finalize_blocks has failed")?;
+	}
+}
+
+/// Builds `count` benchmark validator secret keys, derived deterministically from their index
+/// so the same fixture is reproducible across runs. Unlike synthetic addresses, these let us
+/// produce empty-step signatures that recover to an actual validator.
+fn bench_validator_secrets(count: u32) -> Vec<SecretKey> {
+	(0..count)
+		.map(|index| {
+			let mut seed = [0xCDu8; 32];
+			seed[0..4].copy_from_slice(&(index + 1).to_be_bytes());
+			SecretKey::parse(&seed).expect("index + 1 != 0, so seed is a valid non-zero secret key; qed")
+		})
+		.collect()
+}
+
+/// Returns the address that `secret`'s signatures recover to.
+fn address_from_secret(secret: &SecretKey) -> Address {
+	let public = PublicKey::from_secret_key(secret);
+	let mut uncompressed = [0u8; 64];
+	uncompressed.copy_from_slice(&public.serialize()[1..]);
+	public_to_address(&uncompressed)
+}
+
+/// Wraps a single validators set into a `ValidatorsSets`, as if it had been active since the
+/// dawn of time - the benchmarks never exercise validator-set transitions themselves.
+fn single_validators_set(validators: &[Address]) -> ValidatorsSets {
+	let mut sets = BTreeMap::new();
+	sets.insert(Default::default(), validators);
+	sets
+}
+
+/// Builds a `SealedEmptyStep` for `step`, signed by `secret` over `parent_hash` - a real
+/// signature that `secp256k1_ecdsa_recover` will successfully recover, unlike an all-zero
+/// signature, which is rejected before any EC recovery work is done.
+fn sign_empty_step(step: u64, parent_hash: &H256, secret: &SecretKey) -> SealedEmptyStep {
+	let unsigned = SealedEmptyStep {
+		step,
+		signature: Default::default(),
+	};
+	let message = unsigned.message(parent_hash);
+	let (signature, recovery_id) = sign(&Message::parse(message.as_fixed_bytes()), secret);
+
+	let mut raw_signature = [0u8; 65];
+	raw_signature[..64].copy_from_slice(&signature.serialize());
+	raw_signature[64] = recovery_id.serialize();
+
+	SealedEmptyStep {
+		step,
+		signature: H520::from(raw_signature),
+	}
+}
+
+/// Imports `depth` headers on top of genesis into `storage`, cycling authorship over
+/// `validators` so that finality is always reachable, and attaches `empty_steps` maximal-cost
+/// `SealedEmptyStep`s to every imported header, all signed by `empty_step_signer` (which must be
+/// one of `validators`, so the votes they carry are actually counted) and numbered with a
+/// globally increasing step counter, so the same `(signer, step)` pair never recurs across the
+/// ancestry and an honest chain is never mistaken for an equivocation. Returns the id that
+/// should be passed as `best_finalized` (genesis) and the imported headers, oldest first.
+fn import_chain<S: Storage>(
+	storage: &mut S,
+	validators: &[Address],
+	empty_step_signer: &SecretKey,
+	depth: u32,
+	empty_steps: u32,
+) -> (primitives::HeaderId, Vec<Header>) {
+	let mut parent_hash = H256::default();
+	let mut headers = Vec::new();
+	let mut next_step = 0u64;
+	for number in 1..=depth {
+		// worst case: every signature must go through a full, successful `secp256k1_ecdsa_recover`
+		// for a signer that is a validator, so `add_signers_votes` runs to completion instead of
+		// bailing out on the first signer
+		let sealed_empty_steps = (0..empty_steps)
+			.map(|_| {
+				let step = next_step;
+				next_step += 1;
+				sign_empty_step(step, &parent_hash, empty_step_signer)
+			})
+			.collect::<Vec<_>>();
+
+		let header = Header {
+			author: validators[(number as usize - 1) % validators.len()],
+			parent_hash,
+			number: number as u64,
+			seal: vec![Default::default(), codec::Encode::encode(&sealed_empty_steps)],
+			..Default::default()
+		};
+		parent_hash = header.compute_hash();
+		storage.insert_header(crate::HeaderToImport {
+			context: storage.import_context(None, &header.parent_hash).expect("parent is always imported"),
+			is_best: true,
+			id: header.compute_id(),
+			header: header.clone(),
+			total_difficulty: 0.into(),
+			enacted_change: None,
+			scheduled_change: None,
+			finality_votes: Default::default(),
+		});
+		headers.push(header);
+	}
+
+	(Default::default(), headers)
+}
+
+/// Builds the real, accumulated `FinalityVotes` snapshot at `parent_header` by replaying the
+/// whole ancestry up to it, and writes it into the finality cache - so a benchmark run that
+/// finalizes `parent_header`'s child actually hits the warm-cache path, instead of silently
+/// degrading to another cache miss.
+fn cache_finality_votes<T: Trait, S: Storage>(storage: &S, validators_sets: &ValidatorsSets, parent_header: &Header) {
+	let cached_votes = storage.cached_finality_votes(&parent_header.parent_hash, |_| false);
+	let (votes, _misbehaviour) = prepare_votes(
+		cached_votes,
+		0,
+		validators_sets,
+		parent_header.compute_id(),
+		parent_header,
+		None,
+	)
+	.expect("benchmark fixture headers are always signed by a known validator; qed");
+	crate::FinalityCache::<T>::insert(parent_header.compute_hash(), votes);
+}
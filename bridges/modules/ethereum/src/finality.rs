@@ -47,8 +47,40 @@ pub struct FinalityEffects<Submitter> {
 	pub finalized_headers: Vec<(HeaderId, Option<Submitter>)>,
 	/// Finality votes used in computation.
 	pub votes: FinalityVotes<Submitter>,
+	/// Whether the caller should persist `votes` as a fresh `FinalityVotesCachingInterval`
+	/// checkpoint for this header, instead of relying on `unaccounted_ancestry` replay.
+	pub cache_votes: bool,
+	/// Empty-step equivocations detected while computing `votes`: validators that have signed
+	/// two conflicting empty steps at the same step within the unfinalized ancestry.
+	pub misbehaviour: Vec<(Address, EmptyStepEquivocationProof)>,
 }
 
+/// Proof that a validator has signed two conflicting `SealedEmptyStep`s for the same step -
+/// carries both signed steps (and the parent hash each of them attests to) so that the report
+/// is independently verifiable, rather than just a claim.
+#[derive(Clone, RuntimeDebug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct EmptyStepEquivocationProof {
+	/// The step number both empty steps claim.
+	pub step: u64,
+	/// Parent that the first empty step we've seen for this step attests to.
+	pub first_parent: H256,
+	/// The first empty step we've seen for this step.
+	pub first: SealedEmptyStep,
+	/// Parent that the conflicting empty step attests to.
+	pub second_parent: H256,
+	/// The conflicting empty step.
+	pub second: SealedEmptyStep,
+}
+
+/// Validator sets that are (or were) active within the unfinalized ancestry, keyed
+/// by the id of the header that enacted them.
+///
+/// There must be an entry covering `best_finalized` - i.e. the oldest key must have
+/// a number that is not greater than `best_finalized.number` - since blocks that old
+/// are never looked at.
+pub type ValidatorsSets<'a> = BTreeMap<HeaderId, &'a [Address]>;
+
 /// Finality votes for given block.
 #[derive(RuntimeDebug, Decode, Encode)]
 #[cfg_attr(test, derive(Clone, PartialEq))]
@@ -79,20 +111,28 @@ pub struct FinalityAncestor<Submitter> {
 pub fn finalize_blocks<S: Storage>(
 	storage: &S,
 	best_finalized: HeaderId,
-	header_validators: (HeaderId, &[Address]),
+	validators_sets: &ValidatorsSets,
 	id: HeaderId,
 	submitter: Option<&S::Submitter>,
 	header: &Header,
 	two_thirds_majority_transition: u64,
+	finality_votes_caching_interval: Option<u64>,
 ) -> Result<FinalityEffects<S::Submitter>, Error> {
+	// the cache must never be trusted past the oldest validators set we know about, because
+	// votes that were cast under an older (now unknown) set can't be reasoned about
+	let oldest_set_id = validators_sets
+		.keys()
+		.min_by_key(|enacted_at| enacted_at.number)
+		.cloned()
+		.unwrap_or(best_finalized);
+
 	// compute count of voters for every unfinalized block in ancestry
-	let validators = header_validators.1.iter().collect();
-	let votes = prepare_votes(
+	let (votes, misbehaviour) = prepare_votes(
 		storage.cached_finality_votes(&header.parent_hash, |hash| {
-			*hash == header_validators.0.hash || *hash == best_finalized.hash
+			*hash == oldest_set_id.hash || *hash == best_finalized.hash
 		}),
 		best_finalized.number,
-		&validators,
+		validators_sets,
 		id,
 		header,
 		submitter.cloned(),
@@ -102,9 +142,10 @@ pub fn finalize_blocks<S: Storage>(
 	let mut finalized_headers = Vec::new();
 	let mut current_votes = votes.votes.clone();
 	for ancestor in &votes.ancestry {
+		let validators = validators_at(validators_sets, ancestor.id.number)?;
 		if !is_finalized(
 			&validators,
-			&current_votes,
+			current_votes.keys().filter(|voter| validators.contains(voter)).count(),
 			ancestor.id.number >= two_thirds_majority_transition,
 		) {
 			break;
@@ -117,29 +158,64 @@ pub fn finalize_blocks<S: Storage>(
 	Ok(FinalityEffects {
 		finalized_headers,
 		votes,
+		cache_votes: should_cache_votes(id.number, finality_votes_caching_interval),
+		misbehaviour,
 	})
 }
 
+/// Returns true if the full `FinalityVotes` snapshot computed for the header with given
+/// `number` should be written to the cache, as opposed to being reconstructed next time by
+/// replaying `unaccounted_ancestry` on top of the previous cache entry.
+///
+/// With no configured interval, every header is cached, preserving the original behavior.
+fn should_cache_votes(number: u64, caching_interval: Option<u64>) -> bool {
+	match caching_interval {
+		Some(interval) if interval > 1 => number % interval == 0,
+		_ => true,
+	}
+}
+
 /// Returns true if there are enough votes to treat this header as finalized.
-fn is_finalized(
-	validators: &BTreeSet<&Address>,
-	votes: &BTreeMap<Address, u64>,
-	requires_two_thirds_majority: bool,
-) -> bool {
-	(!requires_two_thirds_majority && votes.len() * 2 > validators.len())
-		|| (requires_two_thirds_majority && votes.len() * 3 > validators.len() * 2)
+///
+/// `votes_count` must only count voters that are members of `validators` - across a
+/// validator-set transition the global tally may also hold votes cast by the outgoing set,
+/// which must not count towards a block finalized under the incoming set.
+fn is_finalized(validators: &BTreeSet<&Address>, votes_count: usize, requires_two_thirds_majority: bool) -> bool {
+	(!requires_two_thirds_majority && votes_count * 2 > validators.len())
+		|| (requires_two_thirds_majority && votes_count * 3 > validators.len() * 2)
+}
+
+/// Returns the validators set that was enacted and active when the header with given
+/// `number` was sealed.
+///
+/// Fails if `validators_sets` has no entry that covers `number` - the caller must pass a set
+/// of validators sets that spans the whole unfinalized ancestry, but since that set is built
+/// elsewhere we can't rely on the invariant actually holding.
+fn validators_at<'a>(validators_sets: &'a ValidatorsSets, number: u64) -> Result<BTreeSet<&'a Address>, Error> {
+	validators_sets
+		.iter()
+		.filter(|(enacted_at, _)| enacted_at.number <= number)
+		.max_by_key(|(enacted_at, _)| enacted_at.number)
+		.map(|(_, validators)| validators.iter().collect())
+		.ok_or(Error::NotValidator)
 }
 
 /// Prepare 'votes' of header and its ancestors' signers.
-fn prepare_votes<Submitter>(
+///
+/// `pub(crate)` so that benchmarks can build a real `FinalityVotes` snapshot to seed the
+/// finality cache with, instead of exercising the cache-miss path under a different name.
+pub(crate) fn prepare_votes<Submitter>(
 	mut cached_votes: CachedFinalityVotes<Submitter>,
 	best_finalized_number: u64,
-	validators: &BTreeSet<&Address>,
+	validators_sets: &ValidatorsSets,
 	id: HeaderId,
 	header: &Header,
 	submitter: Option<Submitter>,
-) -> Result<FinalityVotes<Submitter>, Error> {
-	// this fn can only work with single validators set
+) -> Result<(FinalityVotes<Submitter>, Vec<(Address, EmptyStepEquivocationProof)>), Error> {
+	// the header may have been sealed under a different validators set than some of
+	// its ancestors - each ancestor's votes are checked against the set that was
+	// active when it was sealed, further down
+	let validators = validators_at(validators_sets, id.number)?;
 	if !validators.contains(&header.author) {
 		return Err(Error::NotValidator);
 	}
@@ -150,6 +226,9 @@ fn prepare_votes<Submitter>(
 	// so the only thing we need to do is:
 	// 1) remove votes from blocks that have been finalized after B has been inserted;
 	// 2) add votes from B descendants
+	// B is the most recent header for which we still have a cached snapshot - with
+	// `FinalityVotesCachingInterval` configured, intermediate headers have none, so
+	// `unaccounted_ancestry` may be longer than one header and gets fully replayed below
 	let mut votes = cached_votes.votes.unwrap_or_default();
 
 	// remove votes from finalized blocks
@@ -162,15 +241,22 @@ fn prepare_votes<Submitter>(
 		remove_signers_votes(&old_ancestor.signers, &mut votes.votes);
 	}
 
-	// add votes from new blocks
-	let mut parent_empty_step_signers = empty_steps_signers(header);
+	// add votes from new blocks, keeping track of every empty step we see so that a
+	// validator signing two conflicting empty steps for the same step number - an
+	// equivocation - is caught rather than silently counted twice
+	let mut seen_empty_steps = BTreeMap::new();
+	let mut misbehaviour = Vec::new();
+	let mut parent_empty_steps = empty_steps_entries(header);
 	let mut unaccounted_ancestry = VecDeque::new();
 	while let Some((ancestor_id, ancestor_submitter, ancestor)) = cached_votes.unaccounted_ancestry.pop_front() {
-		let mut signers = empty_steps_signers(&ancestor);
-		sp_std::mem::swap(&mut signers, &mut parent_empty_step_signers);
+		let mut empty_steps = empty_steps_entries(&ancestor);
+		sp_std::mem::swap(&mut empty_steps, &mut parent_empty_steps);
+
+		let mut signers = record_empty_steps(&empty_steps, &mut seen_empty_steps, &mut misbehaviour);
 		signers.insert(ancestor.author);
 
-		add_signers_votes(validators, &signers, &mut votes.votes)?;
+		let ancestor_validators = validators_at(validators_sets, ancestor_id.number)?;
+		add_signers_votes(&ancestor_validators, &signers, &mut votes.votes)?;
 
 		unaccounted_ancestry.push_front(FinalityAncestor {
 			id: ancestor_id,
@@ -190,7 +276,45 @@ fn prepare_votes<Submitter>(
 		signers: header_signers,
 	});
 
-	Ok(votes)
+	Ok((votes, misbehaviour))
+}
+
+/// Records `empty_steps` seen while walking the ancestry, reporting an equivocation whenever
+/// the same signer has already been seen for the same step attesting to a different parent,
+/// or carrying a different signature over the same parent. Returns the set of (deduplicated)
+/// signers of `empty_steps`.
+fn record_empty_steps(
+	empty_steps: &[(Address, SealedEmptyStep, H256)],
+	seen_empty_steps: &mut BTreeMap<(Address, u64), (SealedEmptyStep, H256)>,
+	misbehaviour: &mut Vec<(Address, EmptyStepEquivocationProof)>,
+) -> BTreeSet<Address> {
+	let mut signers = BTreeSet::new();
+	for (signer, step, parent_hash) in empty_steps {
+		signers.insert(*signer);
+
+		match seen_empty_steps.entry((*signer, step.step)) {
+			Entry::Occupied(first) => {
+				let (first_step, first_parent_hash) = first.get();
+				if first_step.signature != step.signature || first_parent_hash != parent_hash {
+					misbehaviour.push((
+						*signer,
+						EmptyStepEquivocationProof {
+							step: step.step,
+							first_parent: *first_parent_hash,
+							first: first_step.clone(),
+							second_parent: *parent_hash,
+							second: step.clone(),
+						},
+					));
+				}
+			}
+			Entry::Vacant(entry) => {
+				entry.insert((step.clone(), *parent_hash));
+			}
+		}
+	}
+
+	signers
 }
 
 /// Increase count of 'votes' for every passed signer.
@@ -222,19 +346,25 @@ fn remove_signers_votes(signers_to_remove: &BTreeSet<Address>, votes: &mut BTree
 					*entry.get_mut() -= 1;
 				}
 			}
-			Entry::Vacant(_) => unreachable!("we only remove signers that have been added; qed"),
+			// normally we only remove signers that have been added, but a signer that
+			// was only ever active under a validators set that has since been replaced
+			// may legitimately have no entry left in the current tally
+			Entry::Vacant(_) => {}
 		}
 	}
 }
 
-/// Returns unique set of empty steps signers.
-fn empty_steps_signers(header: &Header) -> BTreeSet<Address> {
+/// Returns `(signer, step, parent it attests to)` for every empty step attached to `header`
+/// whose signature recovers to a valid address.
+fn empty_steps_entries(header: &Header) -> Vec<(Address, SealedEmptyStep, H256)> {
 	header
 		.empty_steps()
 		.into_iter()
 		.flat_map(|steps| steps)
-		.filter_map(|step| empty_step_signer(&step, &header.parent_hash))
-		.collect::<BTreeSet<_>>()
+		.filter_map(|step| {
+			empty_step_signer(&step, &header.parent_hash).map(|signer| (signer, step, header.parent_hash))
+		})
+		.collect()
 }
 
 /// Returns author of empty step signature.
@@ -261,6 +391,12 @@ mod tests {
 	use crate::{BridgeStorage, FinalityCache, HeaderToImport};
 	use frame_support::StorageMap;
 
+	/// Wraps a single validators set into a `ValidatorsSets`, as if it had been active
+	/// since the dawn of time.
+	fn single_validators_set(validators: &[Address]) -> ValidatorsSets {
+		vec![(HeaderId::default(), validators)].into_iter().collect()
+	}
+
 	#[test]
 	fn verifies_header_author() {
 		custom_test_ext(genesis(), validators_addresses(5)).execute_with(|| {
@@ -268,11 +404,12 @@ mod tests {
 				finalize_blocks(
 					&BridgeStorage::<TestRuntime>::new(),
 					Default::default(),
-					(Default::default(), &[]),
+					&single_validators_set(&[]),
 					Default::default(),
 					None,
 					&Header::default(),
 					0,
+					None,
 				),
 				Err(Error::NotValidator),
 			);
@@ -308,11 +445,12 @@ mod tests {
 				finalize_blocks(
 					&storage,
 					Default::default(),
-					(Default::default(), &validators_addresses(5)),
+					&single_validators_set(&validators_addresses(5)),
 					id1,
 					None,
 					&header_to_import.header,
 					u64::max_value(),
+					None,
 				)
 				.map(|eff| eff.finalized_headers),
 				Ok(Vec::new()),
@@ -332,11 +470,12 @@ mod tests {
 				finalize_blocks(
 					&storage,
 					Default::default(),
-					(Default::default(), &validators_addresses(5)),
+					&single_validators_set(&validators_addresses(5)),
 					id2,
 					None,
 					&header_to_import.header,
 					u64::max_value(),
+					None,
 				)
 				.map(|eff| eff.finalized_headers),
 				Ok(Vec::new()),
@@ -356,11 +495,12 @@ mod tests {
 				finalize_blocks(
 					&storage,
 					Default::default(),
-					(Default::default(), &validators_addresses(5)),
+					&single_validators_set(&validators_addresses(5)),
 					id3,
 					None,
 					&header_to_import.header,
 					u64::max_value(),
+					None,
 				)
 				.map(|eff| eff.finalized_headers),
 				Ok(vec![(id1, None)]),
@@ -408,18 +548,21 @@ mod tests {
 					}),
 				},
 				2,
-				&validators.iter().collect(),
+				&single_validators_set(&validators),
 				header5.compute_id(),
 				&header5,
 				None,
 			)
 			.unwrap(),
-			FinalityVotes {
-				votes: vec![(validators[2], 1), (validators[3], 1), (validators[4], 1),]
-					.into_iter()
-					.collect(),
-				ancestry: ancestry[2..].iter().cloned().collect(),
-			},
+			(
+				FinalityVotes {
+					votes: vec![(validators[2], 1), (validators[3], 1), (validators[4], 1),]
+						.into_iter()
+						.collect(),
+					ancestry: ancestry[2..].iter().cloned().collect(),
+				},
+				Vec::new(),
+			),
 		);
 	}
 
@@ -473,13 +616,13 @@ mod tests {
 				prepare_votes(
 					storage.cached_finality_votes(&hashes.get(5).unwrap(), |_| false,),
 					0,
-					&validators_addresses.iter().collect(),
+					&single_validators_set(&validators_addresses),
 					id7,
 					headers.get(6).unwrap(),
 					None,
 				)
 				.unwrap(),
-				expected_votes_at_7,
+				(expected_votes_at_7.clone(), Vec::new()),
 			);
 
 			// cached votes at #5
@@ -500,13 +643,13 @@ mod tests {
 				prepare_votes(
 					storage.cached_finality_votes(&hashes.get(5).unwrap(), |_| false,),
 					0,
-					&validators_addresses.iter().collect(),
+					&single_validators_set(&validators_addresses),
 					id7,
 					headers.get(6).unwrap(),
 					None,
 				)
 				.unwrap(),
-				expected_votes_at_7,
+				(expected_votes_at_7.clone(), Vec::new()),
 			);
 
 			// when we're inserting header#7 and last finalized header is 3:
@@ -524,13 +667,13 @@ mod tests {
 				prepare_votes(
 					storage.cached_finality_votes(&hashes.get(5).unwrap(), |hash| *hash == hashes[2],),
 					3,
-					&validators_addresses.iter().collect(),
+					&single_validators_set(&validators_addresses),
 					id7,
 					headers.get(6).unwrap(),
 					None,
 				)
 				.unwrap(),
-				expected_votes_at_7,
+				(expected_votes_at_7.clone(), Vec::new()),
 			);
 		});
 	}